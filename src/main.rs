@@ -4,35 +4,512 @@
 // 1. Regions with clipping at both ends
 // 2. Regions with zero coverage
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::fs::File;
 use std::io::Write;
-use rust_htslib::bam::{self, Read};
+use std::sync::{Arc, Mutex};
+use rust_htslib::bam::{self, FetchDefinition, Read};
 use rust_htslib::bam::record::CigarStringView;
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 
 #[derive(Debug)]
 struct ContigData {
-    coverage: Vec<u32>,
+    // Coverage is piecewise-constant between CIGAR boundaries, so instead of a
+    // dense `vec![0; length]` we accumulate signed depth deltas: a read covering
+    // [start, end) records +1 at `start` and -1 at `end`. A single prefix sweep
+    // (`coverage_runs`) turns the deltas into coverage runs, which keeps memory
+    // proportional to the number of mapping boundaries rather than to the whole
+    // genome.
+    deltas: BTreeMap<usize, i64>,
     clipping: HashMap<usize, u32>,
     length: usize,
 }
 
+// Resolve the coverage at `pos` by binary-searching a sorted, gap-free list of
+// `(start, end, cov)` runs as produced by `ContigData::coverage_runs`.
+fn coverage_at(runs: &[(usize, usize, u32)], pos: usize) -> u32 {
+    match runs.binary_search_by(|&(start, end, _)| {
+        if pos < start {
+            std::cmp::Ordering::Greater
+        } else if pos >= end {
+            std::cmp::Ordering::Less
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    }) {
+        Ok(i) => runs[i].2,
+        Err(_) => 0,
+    }
+}
+
+// A group of nearby clipping breakpoints collapsed into one candidate event.
+// Long-read mapping scatters a single structural breakpoint across many reads
+// at slightly different offsets, so we fold them back together before reporting.
+#[derive(Debug)]
+struct ClipCluster {
+    start: usize,    // leftmost clipped position (0-based, inclusive)
+    end: usize,      // rightmost clipped position + 1 (half-open)
+    pos: usize,      // representative position (the best-supported offset)
+    clipping: u32,   // summed clipped-read support across the cluster
+    cov: u32,        // peak coverage observed within the cluster
+}
+
+// Cumulative coverage-bases up to each run boundary: `cum[i]` is the integral
+// of coverage over [0, runs[i].start). Built once per contig so flank sums cost
+// O(log R) each instead of a full linear scan over the runs.
+fn coverage_cumsum(runs: &[(usize, usize, u32)]) -> Vec<u64> {
+    let mut cum = Vec::with_capacity(runs.len() + 1);
+    let mut total = 0u64;
+    cum.push(0);
+    for &(start, end, cov) in runs {
+        total += cov as u64 * (end - start) as u64;
+        cum.push(total);
+    }
+    cum
+}
+
+// Integral of coverage over [0, pos), resolved by binary-searching the sorted
+// runs and adding the partial run that `pos` falls inside.
+fn coverage_integral(runs: &[(usize, usize, u32)], cum: &[u64], pos: usize) -> u64 {
+    match runs.binary_search_by(|&(start, end, _)| {
+        if pos < start {
+            std::cmp::Ordering::Greater
+        } else if pos >= end {
+            std::cmp::Ordering::Less
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    }) {
+        Ok(i) => cum[i] + runs[i].2 as u64 * (pos - runs[i].0) as u64,
+        // `pos` is at or past the final boundary (or the runs are empty).
+        Err(_) => *cum.last().unwrap_or(&0),
+    }
+}
+
+// Integrate coverage over [a, b) using the prefix sums.
+fn coverage_sum(runs: &[(usize, usize, u32)], cum: &[u64], a: usize, b: usize) -> u64 {
+    if b <= a {
+        return 0;
+    }
+    coverage_integral(runs, cum, b) - coverage_integral(runs, cum, a)
+}
+
 impl ContigData {
     fn new(length: usize) -> Self {
-        let coverage = vec![0;length];
-        
         ContigData {
-            coverage,
+            deltas: BTreeMap::new(),
             clipping: HashMap::new(),
             length,
         }
     }
-    
+
     fn add_clipping(&mut self, pos: usize) {
         *self.clipping.entry(pos).or_insert(0) += 1;
     }
+
+    // Record one mapped block [start, end) as a pair of depth deltas.
+    fn add_coverage(&mut self, start: usize, end: usize) {
+        *self.deltas.entry(start).or_insert(0) += 1;
+        *self.deltas.entry(end).or_insert(0) -= 1;
+    }
+
+    // Sweep the deltas into contiguous `(start, end, cov)` runs spanning the
+    // whole contig, filling zero-coverage gaps so the result is gap-free.
+    fn coverage_runs(&self) -> Vec<(usize, usize, u32)> {
+        let mut runs = Vec::new();
+        let mut prev_pos = 0usize;
+        let mut running: i64 = 0;
+
+        for (&pos, &delta) in &self.deltas {
+            let pos = pos.min(self.length);
+            if pos > prev_pos {
+                runs.push((prev_pos, pos, running.max(0) as u32));
+                prev_pos = pos;
+            }
+            running += delta;
+        }
+
+        if prev_pos < self.length {
+            runs.push((prev_pos, self.length, running.max(0) as u32));
+        }
+
+        runs
+    }
+
+    // Cluster clipping positions whose neighbours fall within `window` bp, summing
+    // their support and taking the peak coverage. With `window == 0` every position
+    // stands alone, preserving the original one-row-per-position behaviour.
+    fn clip_clusters(&self, window: usize) -> Vec<ClipCluster> {
+        let mut positions: Vec<(usize, u32)> =
+            self.clipping.iter().map(|(&pos, &count)| (pos, count)).collect();
+        positions.sort_by_key(|&(pos, _)| pos);
+
+        let runs = self.coverage_runs();
+        let mut clusters = Vec::new();
+        let mut i = 0;
+
+        while i < positions.len() {
+            let mut j = i + 1;
+            while j < positions.len() && positions[j].0 - positions[j - 1].0 <= window {
+                j += 1;
+            }
+
+            let slice = &positions[i..j];
+            let start = slice.first().unwrap().0;
+            let end = slice.last().unwrap().0 + 1;
+            let clipping: u32 = slice.iter().map(|&(_, count)| count).sum();
+            let pos = slice.iter().max_by_key(|&&(_, count)| count).unwrap().0;
+            let cov = slice
+                .iter()
+                .map(|&(pos, _)| coverage_at(&runs, pos))
+                .max()
+                .unwrap();
+
+            clusters.push(ClipCluster { start, end, pos, clipping, cov });
+            i = j;
+        }
+
+        clusters
+    }
+
+    // Fold a single mapped read's CIGAR into this contig's coverage and clipping
+    // tallies. Shared by the serial and per-contig parallel code paths.
+    fn add_read(&mut self, read: &bam::Record) {
+        let contig_end = self.length;
+        let mut current_pos = read.pos() as usize;
+        let cigar: CigarStringView = read.cigar();
+
+        // Count the number of CIGAR operations
+        let mut num_tup = 0;
+
+        for op in cigar.iter() {
+            num_tup += 1;
+
+            match op.char() {
+                // If mapping (M, =, X), accumulate a coverage run, increase current position
+                'M' | '=' | 'X' => {
+                    let op_len = op.len() as usize;
+                    self.add_coverage(current_pos, current_pos + op_len);
+                    current_pos += op_len;
+                },
+                // If deletion (D), increase current position
+                'D' => {
+                    current_pos += op.len() as usize;
+                },
+                // If clipping (S, H), then +1 clipping at position
+                'S' | 'H' => {
+                    if num_tup == 1 {
+                        // If at start of contig, skip
+                        if current_pos != 0 {
+                            self.add_clipping(current_pos);
+                        }
+                    } else if current_pos != contig_end {
+                        self.add_clipping(current_pos.saturating_sub(1));
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+// Serial path: walk the BAM once and fill every contig's ContigData in order.
+// Block decompression is still handed to htslib's thread pool when available.
+fn build_serial(
+    bam_file: &str,
+    contigs_size: &HashMap<String, usize>,
+    num_threads: usize,
+) -> Result<HashMap<String, ContigData>, Box<dyn std::error::Error>> {
+    let mut bam = bam::Reader::from_path(bam_file)?;
+    if num_threads > 1 {
+        bam.set_threads(num_threads)?;
+    }
+    let header = bam.header().clone();
+
+    let mut cov_dict: HashMap<String, ContigData> = HashMap::new();
+    let mut read_count = 0;
+
+    for result in bam.records() {
+        let read = result?;
+        read_count += 1;
+
+        if read.is_unmapped() {
+            continue;
+        }
+
+        if read_count % 500 == 0 {
+            eprint!("\rProcessed {} reads", read_count);
+        }
+
+        let contig = String::from_utf8_lossy(header.tid2name(read.tid() as u32)).to_string();
+        let contig_end = *contigs_size.get(&contig).unwrap();
+
+        let contig_struct = cov_dict
+            .entry(contig)
+            .or_insert_with(|| ContigData::new(contig_end));
+        contig_struct.add_read(&read);
+    }
+
+    Ok(cov_dict)
+}
+
+// Parallel path: shard the contigs across a pool of workers, each of which owns
+// its own IndexedReader and fetches exactly the contigs it is handed. Because
+// every ContigData is keyed by a distinct contig there is no write contention,
+// so the per-contig results can simply be collected into one map at the end.
+fn build_parallel(
+    bam_file: &str,
+    work: Vec<(String, u32, usize)>,
+    num_threads: usize,
+) -> Result<HashMap<String, ContigData>, Box<dyn std::error::Error>> {
+    let queue = Arc::new(Mutex::new(work));
+    let results: Arc<Mutex<HashMap<String, ContigData>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..num_threads {
+            let queue = Arc::clone(&queue);
+            let results = Arc::clone(&results);
+            scope.spawn(move || {
+                let mut reader = bam::IndexedReader::from_path(bam_file)
+                    .expect("failed to open indexed BAM in worker");
+                loop {
+                    let item = { queue.lock().unwrap().pop() };
+                    let (contig, tid, length) = match item {
+                        Some(item) => item,
+                        None => break,
+                    };
+
+                    reader
+                        .fetch(FetchDefinition::Region(tid as i32, 0, length as i64))
+                        .expect("failed to fetch contig region");
+
+                    let mut data = ContigData::new(length);
+                    let mut mapped = 0usize;
+                    for result in reader.records() {
+                        let read = result.expect("failed to read record");
+                        if read.is_unmapped() {
+                            continue;
+                        }
+                        mapped += 1;
+                        data.add_read(&read);
+                    }
+
+                    // Match build_serial's contract: a contig only enters
+                    // cov_dict once at least one read has landed on it, so the
+                    // two paths produce identical output for the same BAM.
+                    if mapped > 0 {
+                        results.lock().unwrap().insert(contig, data);
+                    }
+                }
+            });
+        }
+    });
+
+    let results = Arc::try_unwrap(results)
+        .expect("worker threads still hold results")
+        .into_inner()
+        .unwrap();
+    Ok(results)
+}
+
+// N50 of a set of read lengths: the length L such that reads at least L bp long
+// account for at least half of the sampled bases.
+fn read_n50(lengths: &[usize]) -> usize {
+    let mut sorted: Vec<usize> = lengths.to_vec();
+    sorted.sort_unstable();
+    let total: u64 = sorted.iter().map(|&len| len as u64).sum();
+    let mut acc = 0u64;
+    for &len in sorted.iter().rev() {
+        acc += len as u64;
+        if acc * 2 >= total {
+            return len;
+        }
+    }
+    0
+}
+
+// Apply the three self-mapping checks to statistics already gathered from the
+// sample, returning the specific violated assumption on failure. Split out from
+// the BAM I/O so the heuristics can be exercised with synthetic data.
+fn evaluate_provenance(
+    read_lengths: &[usize],
+    max_span: &HashMap<String, usize>,
+    contigs_size: &HashMap<String, usize>,
+    interior_clipped: usize,
+    sampled: usize,
+) -> Result<(), String> {
+    if sampled == 0 {
+        return Err("no mapped reads found in the BAM; cannot verify self-mapping".into());
+    }
+
+    let n50 = read_n50(read_lengths);
+
+    // Median contig length.
+    let mut contig_lens: Vec<usize> = contigs_size.values().copied().collect();
+    contig_lens.sort_unstable();
+    let median_contig = contig_lens[contig_lens.len() / 2];
+
+    // (1) Read-length distribution: in long-read self-assembly the reads that
+    // built the contigs have an N50 that is an appreciable fraction of them.
+    if (n50 as f64) < 0.1 * median_contig as f64 {
+        return Err(format!(
+            "read N50 ({} bp) is tiny relative to the median contig length ({} bp); \
+             this does not look like long reads mapped onto their own assembly",
+            n50, median_contig
+        ));
+    }
+
+    // (2) End-to-end spanning: a contig short enough for a single read to cover
+    // should have at least one near-full-length spanning read. Only judge contigs
+    // that actually received a sampled read — once the sample budget is spent on
+    // the earlier contigs of a coordinate-sorted BAM the rest have no sample, and
+    // treating those as failures would reject almost every real multi-contig BAM.
+    for (contig, &len) in contigs_size {
+        if len <= n50 {
+            if let Some(&span) = max_span.get(contig) {
+                if (span as f64) < 0.9 * len as f64 {
+                    return Err(format!(
+                        "contig '{}' ({} bp) has no read spanning it end-to-end (best span {} bp); \
+                         reads do not appear to originate from this assembly",
+                        contig, len, span
+                    ));
+                }
+            }
+        }
+    }
+
+    // (3) Interior soft-clips: self-mapping reads clip mostly at contig ends.
+    let interior_frac = interior_clipped as f64 / sampled as f64;
+    if interior_frac > 0.5 {
+        return Err(format!(
+            "{:.0}% of sampled reads carry a large soft-clip in a contig interior; \
+             mapping looks inconsistent with self-assembly",
+            interior_frac * 100.0
+        ));
+    }
+
+    Ok(())
+}
+
+// Sanity-check that the BAM really is long reads mapped onto their own
+// assembly. We sample the first N mapped reads and look for the fingerprints of
+// self-mapping: a read N50 on the order of the contig lengths, at least one
+// near-full-length spanning read for contigs short enough to be spanned, and
+// few large soft-clips in contig interiors. On failure we return the specific
+// assumption that was violated so the caller can report it.
+fn run_provenance_check(
+    bam_file: &str,
+    contigs_size: &HashMap<String, usize>,
+    min_dist_to_end: usize,
+    num_threads: usize,
+) -> Result<(), String> {
+    const SAMPLE_SIZE: usize = 10_000;
+    const INTERIOR_CLIP: usize = 100; // soft-clip length considered significant
+
+    let mut bam = bam::Reader::from_path(bam_file).map_err(|e| e.to_string())?;
+    if num_threads > 1 {
+        let _ = bam.set_threads(num_threads);
+    }
+    let header = bam.header().clone();
+
+    let mut read_lengths: Vec<usize> = Vec::new();
+    let mut max_span: HashMap<String, usize> = HashMap::new();
+    let mut total_mapped_bases: u64 = 0;
+    let mut interior_clipped = 0usize;
+    let mut sampled = 0usize;
+
+    for result in bam.records() {
+        if sampled >= SAMPLE_SIZE {
+            break;
+        }
+        let read = result.map_err(|e| e.to_string())?;
+        // Secondary/supplementary records are routine for long reads crossing
+        // structural junctions and legitimately clip at interior positions;
+        // scoring them would make the guardrail reject good self-mapped BAMs.
+        if read.is_unmapped() || read.is_secondary() || read.is_supplementary() {
+            continue;
+        }
+
+        let contig = String::from_utf8_lossy(header.tid2name(read.tid() as u32)).to_string();
+        let contig_len = match contigs_size.get(&contig) {
+            Some(&len) => len,
+            None => continue,
+        };
+        sampled += 1;
+
+        let cigar = read.cigar();
+        let pos = read.pos() as usize;
+        let mut cur = pos;
+        let mut ref_span = 0usize; // reference bases consumed (M/=/X/D/N)
+        let mut query_aln = 0usize; // query bases that align (M/=/X/I)
+        let mut soft_clip = 0usize;
+        let mut has_interior_clip = false;
+
+        for (idx, op) in cigar.iter().enumerate() {
+            let len = op.len() as usize;
+            match op.char() {
+                'M' | '=' | 'X' => {
+                    ref_span += len;
+                    query_aln += len;
+                    cur += len;
+                }
+                'D' | 'N' => {
+                    ref_span += len;
+                    cur += len;
+                }
+                'I' => {
+                    query_aln += len;
+                }
+                'S' | 'H' => {
+                    if op.char() == 'S' {
+                        soft_clip += len;
+                    }
+                    // A large clip whose breakpoint sits away from both contig
+                    // ends is the kind of interior clip that should be rare.
+                    if op.char() == 'S' && len >= INTERIOR_CLIP {
+                        let clip_pos = if idx == 0 { pos } else { cur };
+                        if clip_pos > min_dist_to_end
+                            && contig_len.saturating_sub(clip_pos) > min_dist_to_end
+                        {
+                            has_interior_clip = true;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if has_interior_clip {
+            interior_clipped += 1;
+        }
+
+        read_lengths.push(query_aln + soft_clip);
+        total_mapped_bases += ref_span as u64;
+
+        let entry = max_span.entry(contig).or_insert(0);
+        if ref_span > *entry {
+            *entry = ref_span;
+        }
+    }
+
+    let result = evaluate_provenance(&read_lengths, &max_span, contigs_size, interior_clipped, sampled);
+    if result.is_ok() {
+        let genome_size: u64 = contigs_size.values().map(|&len| len as u64).sum();
+        let mean_cov = if genome_size > 0 {
+            total_mapped_bases as f64 / genome_size as f64
+        } else {
+            0.0
+        };
+        eprintln!(
+            "Provenance check passed (sampled {} reads, N50 {} bp, ~{:.1}x sampled coverage).",
+            sampled,
+            read_n50(&read_lengths),
+            mean_cov
+        );
+    }
+
+    result
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -55,31 +532,58 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .long("clipping-ratio")
             .default_value("1.0")
             .help("Minimum ratio of clipped reads to total coverage to report"))
+        .arg(Arg::new("threads")
+            .long("threads")
+            .default_value("1")
+            .help("Number of worker threads; when >1 and a BAM index is present, contigs are processed in parallel"))
+        .arg(Arg::new("cluster_window")
+            .long("cluster-window")
+            .default_value("0")
+            .help("Merge clipping breakpoints within this many bp into a single cluster (0 = one row per position, the default)"))
+        .arg(Arg::new("format")
+            .long("format")
+            .value_parser(["tsv", "bed", "vcf"])
+            .default_value("tsv")
+            .help("Extra breakpoint output format in addition to the clipping TSV"))
+        .arg(Arg::new("cp_window")
+            .long("cp-window")
+            .default_value("1000")
+            .help("Flank size (bp) used to compare coverage on either side of a candidate changepoint"))
+        .arg(Arg::new("cp_ratio")
+            .long("cp-ratio")
+            .default_value("0.4")
+            .help("Flag a changepoint when min(left,right)/max(left,right) falls below this ratio"))
+        .arg(Arg::new("cp_min_delta")
+            .long("cp-min-delta")
+            .default_value("10")
+            .help("Noise floor: minimum absolute coverage change for a changepoint to be flagged"))
+        .arg(Arg::new("just_do_it")
+            .long("just-do-it")
+            .action(ArgAction::SetTrue)
+            .help("Skip the self-mapping provenance check and run regardless"))
         .get_matches();
 
     let bam_file = matches.get_one::<String>("bam_file").unwrap();
     let output_prefix = matches.get_one::<String>("output_prefix").unwrap();
     let min_dist_to_end: usize = matches.get_one::<String>("min_dist_to_end").unwrap().parse()?;
     let min_clipping_ratio: f64 = matches.get_one::<String>("clipping_ratio").unwrap().parse()?;
-    let just_do_it = true;
-
-    if !just_do_it {
-        eprintln!("This script ONLY makes sense if you are using a BAM file that was made from");
-        eprintln!("mapping long read onto an assembly made with the SAME long reads.");
-        eprintln!("If you are positive that you did JUST that, then re-run this program with");
-        eprintln!("--just-do-it flag.");
-        return Err("Missing --just-do-it flag".into());
-    }
+    let num_threads: usize = matches.get_one::<String>("threads").unwrap().parse()?;
+    let cluster_window: usize = matches.get_one::<String>("cluster_window").unwrap().parse()?;
+    let format = matches.get_one::<String>("format").unwrap().clone();
+    let cp_window: usize = matches.get_one::<String>("cp_window").unwrap().parse()?;
+    let cp_ratio: f64 = matches.get_one::<String>("cp_ratio").unwrap().parse()?;
+    let cp_min_delta: f64 = matches.get_one::<String>("cp_min_delta").unwrap().parse()?;
+    let just_do_it = matches.get_flag("just_do_it");
 
     println!("BAM file: {}", bam_file);
     println!("Length of contig's end to ignore: {}", min_dist_to_end);
 
-    // Open BAM file
-    let mut bam = bam::Reader::from_path(bam_file)?;
-    
+    // Open BAM file (just for the header; the workers reopen it themselves)
+    let bam = bam::Reader::from_path(bam_file)?;
+
     // Get header information to extract reference names and lengths
     let header = bam.header().clone();
-    
+
     // Create a map of contig names to lengths
     let mut contigs_size = HashMap::new();
     for (i, contig) in header.target_names().iter().enumerate() {
@@ -87,141 +591,176 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let contig_len = header.target_len(i as u32).unwrap() as usize;
         contigs_size.insert(contig_name, contig_len);
     }
-    
-    // The main dictionary to store coverage information
-    let mut cov_dict: HashMap<String, ContigData> = HashMap::new();
-    
-    
-    // Read counter
-    let mut read_count = 0;
-    
-    // Process each read in the BAM file
-    for result in bam.records() {
-        let read = result?;
-        read_count += 1;
-        
-        if read.is_unmapped() {
-            continue;
-        }
-        
-        if read_count % 500 == 0 {
-            eprint!("\rProcessed {} reads", read_count);
-        }
-        
-        let contig = String::from_utf8_lossy(header.tid2name(read.tid() as u32)).to_string();
-        let contig_end = contigs_size.get(&contig).unwrap();
-        
-        // Initialize the contig data if it doesn't exist
-        if !cov_dict.contains_key(&contig) {
-            cov_dict.insert(contig.clone(), ContigData::new(*contig_end));
-        }
-        
-        let mut current_pos = read.pos() as usize;
-        let cigar = read.cigar();
-        
-        // Count the number of CIGAR operations
-        let mut num_tup = 0;
-        let contig_struct = cov_dict.get_mut(&contig).unwrap();
-        
-        for op in cigar.iter() {
-            num_tup += 1;
-            
-            match op.char() {
-                // If mapping (M, =, X), compute coverage, increase current position
-                'M' | '=' | 'X' => {
-                    for pos in current_pos..(current_pos + op.len() as usize) {
-                        contig_struct.coverage[pos] += 1
-                    }
-                    current_pos += op.len() as usize;
-                },
-                // If deletion (D), increase current position
-                'D' => {
-                    current_pos += op.len() as usize;
-                },
-                // If clipping (S, H), then +1 clipping at position
-                'S' | 'H' => {
-                    if num_tup == 1 {
-                        // If at start of contig, skip
-                        if current_pos != 0 {
-                            contig_struct.add_clipping(current_pos);
-                        }
-                    } else if current_pos != *contig_end {
-                        contig_struct.add_clipping(current_pos.saturating_sub(1));
-                    }
-                },
-                _ => {}
-            }
+
+    // These heuristics are only valid when the BAM is self-mapping (long reads
+    // mapped onto an assembly built from those same reads). Unless the user
+    // asserts that with --just-do-it, verify it before producing any output.
+    if !just_do_it {
+        if let Err(reason) = run_provenance_check(bam_file, &contigs_size, min_dist_to_end, num_threads) {
+            eprintln!("This script ONLY makes sense for a BAM of long reads mapped onto an");
+            eprintln!("assembly made with the SAME long reads. The provenance check failed:");
+            eprintln!("  {}", reason);
+            eprintln!("If you are positive the input is self-mapping, re-run with --just-do-it.");
+            return Err("BAM failed the self-mapping provenance check".into());
         }
     }
+
+    // A BAM index (.bai/.csi) is required for the per-contig fetch; without one
+    // we cannot seek, so fall back to the single-threaded streaming path.
+    let has_index = Path::new(&format!("{}.bai", bam_file)).exists()
+        || Path::new(&format!("{}.csi", bam_file)).exists();
+
+    // The main dictionary to store coverage information
+    let cov_dict: HashMap<String, ContigData> = if num_threads > 1 && has_index {
+        let work: Vec<(String, u32, usize)> = header
+            .target_names()
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                (
+                    String::from_utf8_lossy(name).to_string(),
+                    i as u32,
+                    header.target_len(i as u32).unwrap() as usize,
+                )
+            })
+            .collect();
+        build_parallel(bam_file, work, num_threads)?
+    } else {
+        build_serial(bam_file, &contigs_size, num_threads)?
+    };
+
     println!("\nRead processing complete");
 
     // Write clipping output file
     let clipping_output = format!("{}-clipping.txt", output_prefix);
     println!("Output file: {}", clipping_output);
-    
+
     let mut clipping_file = File::create(&clipping_output)?;
     writeln!(clipping_file, "contig\tlength\tpos\trelative_pos\tcov\tclipping\tclipping_ratio")?;
-    
+
+    // Clusters that pass the reporting filters, retained for the optional BED/VCF
+    // emission below so the two stay consistent with the TSV.
+    let mut reported: Vec<(String, usize, ClipCluster)> = Vec::new();
+
     for (contig, data) in &cov_dict {
         let contig_length = data.length;
-        
-        for (&pos, &clipping) in &data.clipping {
-            let cov = data.coverage[pos];
+
+        for cluster in data.clip_clusters(cluster_window) {
+            let pos = cluster.pos;
+            let cov = cluster.cov;
+            let clipping = cluster.clipping;
             let clipping_ratio = clipping as f64 / cov as f64;
             let relative_pos = pos as f64 / contig_length as f64;
-            
-            if clipping_ratio >= min_clipping_ratio && 
-               pos > min_dist_to_end && 
+
+            if clipping_ratio >= min_clipping_ratio &&
+               pos > min_dist_to_end &&
                contig_length - pos > min_dist_to_end {
                 writeln!(
                     clipping_file,
                     "{}\t{}\t{}\t{}\t{}\t{}\t{}",
                     contig, contig_length, pos, relative_pos, cov, clipping, clipping_ratio
                 )?;
+                reported.push((contig.clone(), contig_length, cluster));
+            }
+        }
+    }
+
+    // cov_dict iterates in randomized HashMap order; sort by (contig, pos) so the
+    // BED/VCF records are coordinate-sorted as tabix / bcftools indexing expects.
+    reported.sort_by(|a, b| a.0.cmp(&b.0).then(a.2.pos.cmp(&b.2.pos)));
+
+    // Optionally re-emit the same clusters as standards-compliant breakpoint files
+    // that IGV / bcftools can consume directly.
+    match format.as_str() {
+        "bed" => {
+            let bed_output = format!("{}-clipping.bed", output_prefix);
+            println!("Output file: {}", bed_output);
+            let mut bed_file = File::create(&bed_output)?;
+            for (contig, _length, cluster) in &reported {
+                let clipping_ratio = cluster.clipping as f64 / cluster.cov as f64;
+                // BED score is a spec'd integer in [0, 1000]; map the (possibly
+                // >1) clipping ratio onto that range so strict parsers accept it.
+                let score = (clipping_ratio.min(1.0) * 1000.0).round() as u32;
+                writeln!(
+                    bed_file,
+                    "{}\t{}\t{}\tclip_cluster\t{}",
+                    contig, cluster.start, cluster.end, score
+                )?;
             }
         }
+        "vcf" => {
+            let vcf_output = format!("{}-clipping.vcf", output_prefix);
+            println!("Output file: {}", vcf_output);
+            let mut vcf_file = File::create(&vcf_output)?;
+            writeln!(vcf_file, "##fileformat=VCFv4.2")?;
+            writeln!(vcf_file, "##source=bam_error_detector")?;
+            let mut contig_names: Vec<&String> = contigs_size.keys().collect();
+            contig_names.sort();
+            for name in contig_names {
+                writeln!(vcf_file, "##contig=<ID={},length={}>", name, contigs_size[name])?;
+            }
+            writeln!(vcf_file, "##ALT=<ID=BND,Description=\"Breakpoint\">")?;
+            writeln!(vcf_file, "##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">")?;
+            writeln!(vcf_file, "##INFO=<ID=SUPPORT,Number=1,Type=Integer,Description=\"Clipped reads supporting the breakpoint\">")?;
+            writeln!(vcf_file, "##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Read depth at the breakpoint\">")?;
+            writeln!(vcf_file, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO")?;
+            for (i, (contig, _length, cluster)) in reported.iter().enumerate() {
+                writeln!(
+                    vcf_file,
+                    "{}\t{}\tclip_cluster_{}\tN\t<BND>\t.\tPASS\tSVTYPE=BND;SUPPORT={};DP={}",
+                    contig, cluster.pos + 1, i + 1, cluster.clipping, cluster.cov
+                )?;
+            }
+        }
+        _ => {}
     }
-    
+
     // Write zero coverage output file
     let zero_output = format!("{}-zero_cov.txt", output_prefix);
     println!("Output file: {}", zero_output);
-    
+
     let mut zero_file = File::create(&zero_output)?;
     writeln!(zero_file, "contig\tlength\trange\trange_size")?;
-    
+
     for (contig, data) in &cov_dict {
         let contig_length = data.length;
+        let runs = data.coverage_runs();
+
+        // Zero/non-zero transitions can only happen at run boundaries, so drive
+        // the same window state machine off the runs rather than a per-base loop.
+        let cov0 = coverage_at(&runs, 0);
         let mut in_window = false;
         let mut window_start = 0;
-        
-        for pos in 0..contig_length {
-            if data.coverage[pos] == 0 && !in_window {
-                window_start = pos;
+
+        for &(start, _end, cov) in &runs {
+            if cov == 0 && !in_window {
+                window_start = start;
                 in_window = true;
                 write!(zero_file, "{}\t{}\t{}-", contig, contig_length, window_start)?;
-            } else if data.coverage[pos] > 0 && in_window {
-                let window_end = pos;
+            } else if cov > 0 && in_window {
+                let window_end = start;
                 let window_length = window_end - window_start;
                 in_window = false;
                 writeln!(zero_file, "{}\t{}", window_end, window_length)?;
             }
-            
-            // If end of contig
-            if data.coverage[0] == 0 && pos == contig_length - 1 {
-                if in_window {
-                    let window_end = pos + 1;
-                    let window_length = window_end - window_start;
-                    writeln!(zero_file, "{}\t{}", window_end, window_length)?;
-                } else {
-                    let window_start = pos;
-                    let window_end = pos + 1;
-                    let window_length = window_end - window_start;
-                    writeln!(
-                        zero_file,
-                        "{}\t{}\t{}-{}\t{}",
-                        contig, contig_length, window_start, window_end, window_length
-                    )?;
-                }
+        }
+
+        // If end of contig
+        if contig_length > 0 && cov0 == 0 {
+            let pos = contig_length - 1;
+            if in_window {
+                let window_end = pos + 1;
+                let window_length = window_end - window_start;
+                writeln!(zero_file, "{}\t{}", window_end, window_length)?;
+            } else {
+                let window_start = pos;
+                let window_end = pos + 1;
+                let window_length = window_end - window_start;
+                writeln!(
+                    zero_file,
+                    "{}\t{}\t{}-{}\t{}",
+                    contig, contig_length, window_start, window_end, window_length
+                )?;
             }
         }
 
@@ -229,7 +768,154 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             writeln!(zero_file, "");
         }
     }
-    
+
+    // Write coverage-discontinuity output file
+    //
+    // Zero-coverage gaps only catch total dropouts; the commoner long-read
+    // misassembly signature is an abrupt coverage cliff (e.g. 80x -> 15x) at a
+    // junction. At every run boundary we compare the mean coverage in the left
+    // and right flanks and flag the boundary when the flanks diverge sharply.
+    let cov_drop_output = format!("{}-cov_drop.txt", output_prefix);
+    println!("Output file: {}", cov_drop_output);
+
+    let mut cov_drop_file = File::create(&cov_drop_output)?;
+    writeln!(cov_drop_file, "contig\tpos\tleft_cov\tright_cov\tratio\tclip_support\thigh_confidence")?;
+
+    for (contig, data) in &cov_dict {
+        let contig_length = data.length;
+        let runs = data.coverage_runs();
+        let cum = coverage_cumsum(&runs);
+        let clusters = data.clip_clusters(cluster_window);
+
+        // Candidate changepoints only ever sit on run boundaries, since coverage
+        // is flat in between; evaluate the flank test there and nowhere else.
+        let mut flagged: Vec<(usize, f64, f64, f64)> = Vec::new();
+        for &(start, _end, _cov) in &runs {
+            let pos = start;
+            if pos < cp_window || pos + cp_window > contig_length {
+                continue;
+            }
+
+            let left_cov = coverage_sum(&runs, &cum, pos - cp_window, pos) as f64 / cp_window as f64;
+            let right_cov = coverage_sum(&runs, &cum, pos, pos + cp_window) as f64 / cp_window as f64;
+
+            let hi = left_cov.max(right_cov);
+            let lo = left_cov.min(right_cov);
+            let ratio = if hi > 0.0 { lo / hi } else { 1.0 };
+
+            if ratio < cp_ratio && (left_cov - right_cov).abs() >= cp_min_delta {
+                flagged.push((pos, left_cov, right_cov, ratio));
+            }
+        }
+
+        // Merge consecutive flagged boundaries within a flank width into one
+        // event, keeping the sharpest (lowest-ratio) position as its locus.
+        let mut i = 0;
+        while i < flagged.len() {
+            let mut j = i + 1;
+            while j < flagged.len() && flagged[j].0 - flagged[j - 1].0 <= cp_window {
+                j += 1;
+            }
+
+            let event = *flagged[i..j]
+                .iter()
+                .min_by(|a, b| a.3.partial_cmp(&b.3).unwrap())
+                .unwrap();
+            let (pos, left_cov, right_cov, ratio) = event;
+
+            // A coverage cliff co-located with a clipping spike (a cluster that
+            // passes --clipping-ratio) is a high-confidence misassembly; either
+            // signal alone is noisy.
+            let clip_support: u32 = clusters
+                .iter()
+                .filter(|c| (c.pos as i64 - pos as i64).unsigned_abs() as usize <= cp_window)
+                .filter(|c| c.clipping as f64 / c.cov as f64 >= min_clipping_ratio)
+                .map(|c| c.clipping)
+                .sum();
+            let high_confidence = clip_support > 0;
+
+            writeln!(
+                cov_drop_file,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                contig, pos, left_cov, right_cov, ratio, clip_support, high_confidence
+            )?;
+
+            i = j;
+        }
+    }
+
     println!("Analysis complete!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contigs(pairs: &[(&str, usize)]) -> HashMap<String, usize> {
+        pairs.iter().map(|&(name, len)| (name.to_string(), len)).collect()
+    }
+
+    fn spans(pairs: &[(&str, usize)]) -> HashMap<String, usize> {
+        contigs(pairs)
+    }
+
+    #[test]
+    fn n50_picks_the_half_coverage_length() {
+        // 100 + 200 + 300 + 400 + 500 = 1500; from the top 500 + 400 = 900 >= 750.
+        assert_eq!(read_n50(&[100, 200, 300, 400, 500]), 400);
+    }
+
+    #[test]
+    fn passes_a_healthy_self_mapped_sample() {
+        let contigs = contigs(&[("c1", 5000), ("c2", 3000)]);
+        let spans = spans(&[("c1", 4900), ("c2", 2950)]);
+        let reads = vec![4000, 4500, 5000, 3500];
+        assert!(evaluate_provenance(&reads, &spans, &contigs, 1, 50).is_ok());
+    }
+
+    #[test]
+    fn rejects_when_reads_are_tiny_versus_contigs() {
+        let contigs = contigs(&[("c1", 100_000)]);
+        let spans = spans(&[("c1", 90_000)]);
+        let reads = vec![150, 200, 250]; // N50 ~200 << 0.1 * 100k
+        let err = evaluate_provenance(&reads, &spans, &contigs, 0, 30).unwrap_err();
+        assert!(err.contains("read N50"));
+    }
+
+    #[test]
+    fn rejects_when_a_sampled_contig_is_not_spanned() {
+        let contigs = contigs(&[("c1", 4000)]);
+        let spans = spans(&[("c1", 1000)]); // best span well under 0.9 * len
+        let reads = vec![4000, 4500];
+        let err = evaluate_provenance(&reads, &spans, &contigs, 0, 10).unwrap_err();
+        assert!(err.contains("end-to-end"));
+    }
+
+    #[test]
+    fn skips_contigs_without_a_sampled_read() {
+        // c2 received no sampled reads (absent from max_span); it must not be
+        // treated as an unspanned failure.
+        let contigs = contigs(&[("c1", 4000), ("c2", 3500)]);
+        let spans = spans(&[("c1", 3900)]);
+        let reads = vec![4000, 4200];
+        assert!(evaluate_provenance(&reads, &spans, &contigs, 0, 10).is_ok());
+    }
+
+    #[test]
+    fn rejects_when_interiors_are_heavily_clipped() {
+        let contigs = contigs(&[("c1", 5000)]);
+        let spans = spans(&[("c1", 4900)]);
+        let reads = vec![4000, 4500];
+        // 8 of 10 sampled reads carry an interior soft-clip.
+        let err = evaluate_provenance(&reads, &spans, &contigs, 8, 10).unwrap_err();
+        assert!(err.contains("interior"));
+    }
+
+    #[test]
+    fn rejects_an_empty_sample() {
+        let contigs = contigs(&[("c1", 4000)]);
+        let spans = spans(&[]);
+        assert!(evaluate_provenance(&[], &spans, &contigs, 0, 0).is_err());
+    }
+}